@@ -0,0 +1,247 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    /// A single half-open probe is in flight; every other caller is turned
+    /// away until it records success or failure.
+    HalfOpenProbing,
+}
+
+struct BreakerEntry {
+    consecutive_failures: u32,
+    state: BreakerState,
+}
+
+impl Default for BreakerEntry {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 0,
+            state: BreakerState::Closed,
+        }
+    }
+}
+
+/// Per-`(team_id, host)` circuit breaker so a persistently failing webhook
+/// endpoint stops being hammered with connection attempts and DNS lookups.
+///
+/// Opens after `failure_threshold` consecutive `RetryableError`/`FatalError`
+/// outcomes, short-circuits calls for `cooldown`, then allows a single
+/// half-open probe that closes the circuit on success or re-opens it on
+/// failure.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    entries: Arc<DashMap<(String, String), BreakerEntry>>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            entries: Arc::new(DashMap::new()),
+            failure_threshold,
+            cooldown,
+        }
+    }
+
+    /// Checks whether a call to `(team_id, host)` may proceed, returning a
+    /// guard the caller must resolve with [`ProbeGuard::record_success`] or
+    /// [`ProbeGuard::record_failure`].
+    ///
+    /// If a guard that took the single half-open probe slot is dropped
+    /// without either being called - e.g. an early return on the way to the
+    /// HTTP request - it records a failure on drop instead of leaking the
+    /// slot, which would otherwise wedge the breaker in `HalfOpenProbing`
+    /// forever.
+    pub fn check(&self, team_id: &str, host: &str) -> ProbeGuard {
+        let mut entry = self
+            .entries
+            .entry((team_id.to_string(), host.to_string()))
+            .or_default();
+
+        let (allowed, took_probe) = match entry.state {
+            BreakerState::Closed => (true, false),
+            BreakerState::HalfOpenProbing => (false, false),
+            BreakerState::Open { until } => {
+                if Instant::now() >= until {
+                    entry.state = BreakerState::HalfOpenProbing;
+                    (true, true)
+                } else {
+                    (false, false)
+                }
+            }
+        };
+
+        ProbeGuard {
+            breaker: self.clone(),
+            team_id: team_id.to_string(),
+            host: host.to_string(),
+            allowed,
+            took_probe,
+            resolved: false,
+        }
+    }
+
+    fn record_success(&self, team_id: &str, host: &str) {
+        if let Some(mut entry) = self
+            .entries
+            .get_mut(&(team_id.to_string(), host.to_string()))
+        {
+            entry.consecutive_failures = 0;
+            entry.state = BreakerState::Closed;
+        }
+    }
+
+    fn record_failure(&self, team_id: &str, host: &str) {
+        let mut entry = self
+            .entries
+            .entry((team_id.to_string(), host.to_string()))
+            .or_default();
+
+        entry.consecutive_failures += 1;
+        let was_half_open = matches!(entry.state, BreakerState::HalfOpenProbing);
+        if was_half_open || entry.consecutive_failures >= self.failure_threshold {
+            entry.state = BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            };
+        }
+    }
+
+    #[cfg(test)]
+    fn is_open(&self, team_id: &str, host: &str) -> bool {
+        matches!(
+            self.entries
+                .get(&(team_id.to_string(), host.to_string()))
+                .map(|e| e.state),
+            Some(BreakerState::Open { .. })
+        )
+    }
+}
+
+/// Outcome tracker for a single `dispatch()` call returned by
+/// [`CircuitBreaker::check`]. Must be resolved via `record_success` or
+/// `record_failure`; see the `Drop` impl for what happens if it isn't.
+pub struct ProbeGuard {
+    breaker: CircuitBreaker,
+    team_id: String,
+    host: String,
+    allowed: bool,
+    took_probe: bool,
+    resolved: bool,
+}
+
+impl ProbeGuard {
+    /// Whether the caller should proceed with the dispatch attempt at all.
+    pub fn allowed(&self) -> bool {
+        self.allowed
+    }
+
+    pub fn record_success(mut self) {
+        self.breaker.record_success(&self.team_id, &self.host);
+        self.resolved = true;
+    }
+
+    pub fn record_failure(mut self) {
+        self.breaker.record_failure(&self.team_id, &self.host);
+        self.resolved = true;
+    }
+}
+
+impl Drop for ProbeGuard {
+    fn drop(&mut self) {
+        if self.took_probe && !self.resolved {
+            self.breaker.record_failure(&self.team_id, &self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_allows_and_stays_closed_on_success() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        let guard = breaker.check("team", "host");
+        assert!(guard.allowed());
+        guard.record_success();
+        assert!(!breaker.is_open("team", "host"));
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        for _ in 0..2 {
+            breaker.check("team", "host").record_failure();
+        }
+        assert!(!breaker.is_open("team", "host"));
+        breaker.check("team", "host").record_failure();
+        assert!(breaker.is_open("team", "host"));
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.check("team", "host").record_failure();
+        breaker.check("team", "host").record_failure();
+        breaker.check("team", "host").record_success();
+        breaker.check("team", "host").record_failure();
+        breaker.check("team", "host").record_failure();
+        assert!(!breaker.is_open("team", "host"));
+    }
+
+    #[test]
+    fn stays_open_until_cooldown_elapses() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.check("team", "host").record_failure();
+        assert!(!breaker.check("team", "host").allowed());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check("team", "host").allowed());
+    }
+
+    #[test]
+    fn only_a_single_half_open_probe_is_allowed() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.check("team", "host").record_failure();
+        std::thread::sleep(Duration::from_millis(15));
+
+        let probe = breaker.check("team", "host");
+        assert!(probe.allowed());
+        // A concurrent caller during the same half-open window is turned away.
+        assert!(!breaker.check("team", "host").allowed());
+        probe.record_success();
+    }
+
+    #[test]
+    fn half_open_failure_reopens_the_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.check("team", "host").record_failure();
+        std::thread::sleep(Duration::from_millis(15));
+
+        breaker.check("team", "host").record_failure();
+        assert!(breaker.is_open("team", "host"));
+    }
+
+    #[test]
+    fn dropping_an_unresolved_probe_does_not_wedge_the_breaker() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.check("team", "host").record_failure();
+        std::thread::sleep(Duration::from_millis(15));
+
+        {
+            let probe = breaker.check("team", "host");
+            assert!(probe.allowed());
+            // Dropped here without calling record_success/record_failure,
+            // simulating an early return on the way to the HTTP request.
+        }
+
+        // The breaker must have re-opened rather than staying stuck in
+        // HalfOpenProbing, which would otherwise turn away every caller
+        // forever.
+        assert!(breaker.is_open("team", "host"));
+    }
+}
@@ -1,24 +1,33 @@
 use crate::config::Config;
 use crate::dispatcher::{DispatchResult, WebhookDispatcher};
+use crate::metrics::DispatchMetrics;
 use crate::models::WebhookQueueMessage;
-use anyhow::{Context, Result};
-use futures_lite::StreamExt;
-use lapin::{
-    options::*,
-    types::{FieldTable, LongString},
-    BasicProperties, Connection, ConnectionProperties,
-};
+use crate::queue::{QueueDelivery, RabbitMqQueue, WebhookQueue};
+use crate::ssrf::SsrfPolicy;
+use anyhow::Result;
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 
-const QUEUE_NAME: &str = "webhooks";
-const RETRY_QUEUE_NAME: &str = "webhooks_retry";
-
 pub async fn run(config: Config, mut shutdown_rx: broadcast::Receiver<()>) -> Result<()> {
+    // Built once for the life of the process: `run_inner` re-runs on every
+    // RabbitMQ reconnect, and re-initializing the OTLP pipeline each time
+    // would leak exporters/meter providers into `global::set_meter_provider`.
+    let metrics = config
+        .otel_endpoint
+        .as_deref()
+        .map(DispatchMetrics::init)
+        .transpose()?;
+
     loop {
         let mut shutdown_clone = shutdown_rx.resubscribe();
-        if let Err(e) = run_inner(&config, &mut shutdown_clone).await {
+        let attempt = async {
+            let queue = RabbitMqQueue::connect(&config).await?;
+            run_inner(&config, queue, metrics.clone(), &mut shutdown_clone).await
+        };
+
+        if let Err(e) = attempt.await {
             if shutdown_rx.try_recv().is_ok() {
                 return Ok(());
             }
@@ -30,73 +39,26 @@ pub async fn run(config: Config, mut shutdown_rx: broadcast::Receiver<()>) -> Re
     }
 }
 
-async fn run_inner(config: &Config, shutdown_rx: &mut broadcast::Receiver<()>) -> Result<()> {
-    let conn = Connection::connect(&config.rabbitmq_url, ConnectionProperties::default())
-        .await
-        .context("RabbitMQ connect failed")?;
-    let channel = conn
-        .create_channel()
-        .await
-        .context("Channel create failed")?;
-
-    let mut args = FieldTable::default();
-    args.insert("x-queue-type".into(), LongString::from("quorum").into());
-
-    channel
-        .queue_declare(
-            QUEUE_NAME,
-            QueueDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            args,
-        )
-        .await?;
-
-    let mut retry_args = FieldTable::default();
-    retry_args.insert("x-dead-letter-exchange".into(), LongString::from("").into());
-    retry_args.insert(
-        "x-dead-letter-routing-key".into(),
-        LongString::from(QUEUE_NAME).into(),
-    );
-    retry_args.insert(
-        "x-message-ttl".into(),
-        (config.retry_delay_ms as i64).into(),
+async fn run_inner<Q: WebhookQueue>(
+    config: &Config,
+    queue: Q,
+    metrics: Option<DispatchMetrics>,
+    shutdown_rx: &mut broadcast::Receiver<()>,
+) -> Result<()> {
+    let ssrf_policy = SsrfPolicy::new(&config.ssrf_deny_cidrs, &config.ssrf_allow_cidrs)?;
+    let dispatcher = WebhookDispatcher::new(
+        &config.supabase_url,
+        &config.supabase_service_token,
+        config.circuit_breaker_threshold,
+        Duration::from_secs(config.circuit_breaker_cooldown_secs),
+        ssrf_policy,
+        metrics.clone(),
     );
 
-    channel
-        .queue_declare(
-            RETRY_QUEUE_NAME,
-            QueueDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            retry_args,
-        )
-        .await?;
-
-    channel
-        .basic_qos(config.prefetch_count, BasicQosOptions::default())
-        .await?;
-
-    let mut consumer = channel
-        .basic_consume(
-            QUEUE_NAME,
-            "webhook-dispatcher",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-    let dispatcher = WebhookDispatcher::new(&config.supabase_url, &config.supabase_service_token);
-
     let mut tasks = JoinSet::new();
     let max_concurrent = config.prefetch_count as usize;
 
-    info!(
-        queue = QUEUE_NAME,
-        prefetch = config.prefetch_count,
-        "Consumer started"
-    );
+    info!(prefetch = config.prefetch_count, "Consumer started");
 
     loop {
         tokio::select! {
@@ -106,21 +68,26 @@ async fn run_inner(config: &Config, shutdown_rx: &mut broadcast::Receiver<()>) -
             }
 
             Some(res) = tasks.join_next(), if !tasks.is_empty() => {
-                handle_result(res).await?;
+                handle_result(&queue, res).await?;
+                if let Some(metrics) = &metrics {
+                    metrics.set_in_flight(tasks.len() as i64);
+                }
             }
 
-            delivery = consumer.next(), if tasks.len() < max_concurrent => {
-                match delivery {
-                    Some(Ok(delivery)) => {
+            delivery = queue.consume(), if tasks.len() < max_concurrent => {
+                match delivery? {
+                    Some(delivery) => {
                         let d = dispatcher.clone();
-                        let c = channel.clone();
+                        let q = queue.clone();
                         let max_retries = config.max_retries;
                         tasks.spawn(async move {
-                            let res = process_message(&d, &c, &delivery.data, max_retries).await;
+                            let res = process_message(&d, &q, delivery.data(), max_retries).await;
                             (delivery, res)
                         });
+                        if let Some(metrics) = &metrics {
+                            metrics.set_in_flight(tasks.len() as i64);
+                        }
                     }
-                    Some(Err(e)) => return Err(e.into()),
                     None => break,
                 }
             }
@@ -128,38 +95,37 @@ async fn run_inner(config: &Config, shutdown_rx: &mut broadcast::Receiver<()>) -
     }
 
     while let Some(res) = tasks.join_next().await {
-        handle_result(res).await?;
+        handle_result(&queue, res).await?;
+        if let Some(metrics) = &metrics {
+            metrics.set_in_flight(tasks.len() as i64);
+        }
     }
 
     Ok(())
 }
 
-async fn handle_result(
-    res: Result<(lapin::message::Delivery, Result<()>), tokio::task::JoinError>,
+async fn handle_result<Q: WebhookQueue>(
+    queue: &Q,
+    res: Result<(Q::Delivery, Result<()>), tokio::task::JoinError>,
 ) -> Result<()> {
     match res {
         Ok((delivery, Ok(_))) => {
-            if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                error!(tag = delivery.delivery_tag, error = %e, "Ack failed");
+            if let Err(e) = queue.ack(delivery).await {
+                error!(error = %e, "Ack failed");
             }
         }
         Ok((delivery, Err(e))) => {
-            error!(tag = delivery.delivery_tag, error = %e, "Processing failed, requeueing");
-            let _ = delivery
-                .nack(BasicNackOptions {
-                    multiple: false,
-                    requeue: true,
-                })
-                .await;
+            error!(error = %e, "Processing failed, requeueing");
+            let _ = queue.nack(delivery).await;
         }
         Err(e) => error!(error = %e, "Task panicked"),
     }
     Ok(())
 }
 
-async fn process_message(
+async fn process_message<Q: WebhookQueue>(
     dispatcher: &WebhookDispatcher,
-    channel: &lapin::Channel,
+    queue: &Q,
     data: &[u8],
     max_retries: u32,
 ) -> Result<()> {
@@ -175,7 +141,7 @@ async fn process_message(
 
     match result {
         DispatchResult::Success | DispatchResult::FatalError => Ok(()),
-        DispatchResult::RetryableError => {
+        DispatchResult::RetryableError { status_code, error } => {
             if message.retry_count < max_retries {
                 message.retry_count += 1;
                 info!(
@@ -186,22 +152,14 @@ async fn process_message(
                 );
 
                 let payload = serde_json::to_vec(&message)?;
-                channel
-                    .basic_publish(
-                        "",
-                        RETRY_QUEUE_NAME,
-                        BasicPublishOptions::default(),
-                        &payload,
-                        BasicProperties::default(),
-                    )
-                    .await
-                    .context("Failed to publish retry")?;
+                queue.publish_retry(&payload, message.retry_count).await?;
             } else {
                 warn!(
                     job_id = %message.job_id,
                     attempts = message.retry_count,
-                    "Max retries reached, discarding"
+                    "Max retries reached, dead-lettering"
                 );
+                dispatcher.dead_letter(&message, status_code, error).await?;
             }
             Ok(())
         }
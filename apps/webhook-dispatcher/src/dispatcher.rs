@@ -1,41 +1,72 @@
+use crate::circuit_breaker::CircuitBreaker;
+use crate::metrics::DispatchMetrics;
 use crate::models::{WebhookLog, WebhookQueueMessage};
+use crate::queue::WebhookQueue;
 use crate::signature::sign_payload;
+use crate::ssrf::SsrfPolicy;
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use postgrest::Postgrest;
 use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
-use std::time::Duration;
-use tracing::{error, info, instrument, warn};
+use std::time::{Duration, Instant};
+use tracing::{error, info, instrument, warn, Span};
 use url::Url;
 
+/// A single HMAC secret a team may sign webhooks with, valid only within
+/// `[not_before, not_after)`. Holding more than one lets a team rotate keys
+/// without receivers dropping webhooks mid-rotation.
+#[derive(Debug, Deserialize)]
+struct HmacSecretRow {
+    secret: String,
+    not_before: Option<DateTime<Utc>>,
+    not_after: Option<DateTime<Utc>>,
+}
+
+impl HmacSecretRow {
+    fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.map_or(true, |nb| nb <= now) && self.not_after.map_or(true, |na| now < na)
+    }
+}
+
 #[derive(Clone)]
 pub struct WebhookDispatcher {
     postgrest: Postgrest,
+    circuit_breaker: CircuitBreaker,
+    ssrf_policy: SsrfPolicy,
+    metrics: Option<DispatchMetrics>,
 }
 
 pub enum DispatchResult {
     Success,
     FatalError,
-    RetryableError,
+    RetryableError {
+        status_code: Option<i32>,
+        error: Option<String>,
+    },
 }
 
-fn is_private_ip(ip: IpAddr) -> bool {
-    match ip {
-        IpAddr::V4(ip) => {
-            ip.is_private()
-                || ip.is_loopback()
-                || ip.is_link_local()
-                || ip.is_unspecified()
-                || ip.is_documentation()
-        }
-        IpAddr::V6(ip) => {
-            ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_multicast()
-        }
-    }
+/// Row persisted to `webhook_dead_letters` once a message has exhausted its
+/// retry budget, so operators can inspect and replay it instead of losing it.
+#[derive(Serialize)]
+struct WebhookDeadLetter<'a> {
+    team_id: &'a str,
+    job_id: &'a str,
+    payload: &'a WebhookQueueMessage,
+    status_code: Option<i32>,
+    error: Option<String>,
 }
 
 impl WebhookDispatcher {
-    pub fn new(supabase_url: &str, supabase_service_token: &str) -> Self {
+    pub fn new(
+        supabase_url: &str,
+        supabase_service_token: &str,
+        circuit_breaker_threshold: u32,
+        circuit_breaker_cooldown: Duration,
+        ssrf_policy: SsrfPolicy,
+        metrics: Option<DispatchMetrics>,
+    ) -> Self {
         Self {
             postgrest: Postgrest::new(format!("{}/rest/v1", supabase_url))
                 .insert_header("apikey", supabase_service_token)
@@ -43,40 +74,50 @@ impl WebhookDispatcher {
                     "Authorization",
                     format!("Bearer {}", supabase_service_token),
                 ),
+            circuit_breaker: CircuitBreaker::new(circuit_breaker_threshold, circuit_breaker_cooldown),
+            ssrf_policy,
+            metrics,
         }
     }
 
-    async fn fetch_hmac_secret(&self, team_id: &str) -> Result<Option<String>> {
+    /// Returns every HMAC secret currently valid for `team_id`, i.e. within
+    /// its `not_before`/`not_after` window. During a key rotation this may
+    /// return both the old and new secret.
+    async fn fetch_hmac_secrets(&self, team_id: &str) -> Result<Vec<String>> {
         let response = self
             .postgrest
-            .from("teams")
-            .select("hmac_secret")
-            .eq("id", team_id)
-            .limit(1)
-            .single()
+            .from("team_hmac_secrets")
+            .select("secret,not_before,not_after")
+            .eq("team_id", team_id)
             .execute()
             .await
-            .context("Failed to fetch HMAC secret")?;
+            .context("Failed to fetch HMAC secrets")?;
 
         if !response.status().is_success() {
             warn!(
                 team_id = %team_id,
                 status = response.status().as_u16(),
-                "Failed to fetch HMAC secret from database"
+                "Failed to fetch HMAC secrets from database"
             );
-            return Ok(None);
+            return Ok(Vec::new());
         }
 
-        let data: serde_json::Value = serde_json::from_str(&response.text().await?)?;
-        Ok(data
-            .get("hmac_secret")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string()))
+        let rows: Vec<HmacSecretRow> = serde_json::from_str(&response.text().await?)?;
+        let now = Utc::now();
+        Ok(rows
+            .into_iter()
+            .filter(|row| row.is_valid_at(now))
+            .map(|row| row.secret)
+            .collect())
     }
 
-    #[instrument(skip(self, message), fields(job_id = %message.job_id))]
+    #[instrument(
+        skip(self, message),
+        fields(team_id = %message.team_id, job_id = %message.job_id, host = tracing::field::Empty)
+    )]
     pub async fn dispatch(&self, message: &WebhookQueueMessage) -> Result<DispatchResult> {
-        let hmac_secret = self.fetch_hmac_secret(&message.team_id).await?;
+        let started_at = Instant::now();
+        let hmac_secrets = self.fetch_hmac_secrets(&message.team_id).await?;
 
         let url = match Url::parse(&message.webhook_url) {
             Ok(u) => u,
@@ -84,6 +125,7 @@ impl WebhookDispatcher {
                 warn!(error = %e, url = %message.webhook_url, "Invalid webhook URL");
                 self.log_failure(message, None, format!("Invalid URL: {}", e))
                     .await?;
+                self.record_outcome("fatal", None, message, started_at);
                 return Ok(DispatchResult::FatalError);
             }
         };
@@ -94,32 +136,70 @@ impl WebhookDispatcher {
                 warn!(url = %message.webhook_url, "URL missing host");
                 self.log_failure(message, None, "URL missing host".into())
                     .await?;
+                self.record_outcome("fatal", None, message, started_at);
                 return Ok(DispatchResult::FatalError);
             }
         };
+        Span::current().record("host", host);
 
-        let addrs = match tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(80)))
-            .await
-        {
-            Ok(a) => a,
-            Err(e) => {
-                warn!(error = %e, host = %host, "DNS lookup failed");
-                self.log_failure(message, None, format!("DNS failed: {}", e))
-                    .await?;
-                return Ok(DispatchResult::FatalError);
-            }
-        };
+        // Resolved via the guard on every exit path below so a half-open
+        // probe can never wedge: if this call took the probe slot and
+        // returns without explicitly recording success/failure, `ProbeGuard`
+        // records a failure on drop.
+        let breaker_guard = self.circuit_breaker.check(&message.team_id, host);
+        if !breaker_guard.allowed() {
+            warn!(
+                host = %host,
+                team_id = %message.team_id,
+                "Circuit open for host, short-circuiting dispatch"
+            );
+            self.record_outcome("retryable", None, message, started_at);
+            return Ok(DispatchResult::RetryableError {
+                status_code: None,
+                error: Some(format!("Circuit breaker open for {}", host)),
+            });
+        }
+
+        let addrs: Vec<_> =
+            match tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(80))).await
+            {
+                Ok(a) => a.collect(),
+                Err(e) => {
+                    warn!(error = %e, host = %host, "DNS lookup failed");
+                    self.log_failure(message, None, format!("DNS failed: {}", e))
+                        .await?;
+                    breaker_guard.record_failure();
+                    self.record_outcome("fatal", None, message, started_at);
+                    return Ok(DispatchResult::FatalError);
+                }
+            };
 
-        let target_addr = match addrs.into_iter().find(|addr| !is_private_ip(addr.ip())) {
+        let ips: Vec<IpAddr> = addrs.iter().map(|addr| addr.ip()).collect();
+        if !self.ssrf_policy.all_allowed(&ips) {
+            warn!(host = %host, "Webhook URL resolved to a denied IP range");
+            self.log_failure(message, None, "Resolved to a denied IP range".into())
+                .await?;
+            breaker_guard.record_failure();
+            self.record_outcome("fatal", None, message, started_at);
+            return Ok(DispatchResult::FatalError);
+        }
+
+        let target_addr = match addrs.into_iter().next() {
             Some(addr) => addr,
             None => {
-                warn!(host = %host, "Webhook URL resolved to private/blocked IP");
-                self.log_failure(message, None, "Resolved to private/blocked IP".into())
+                warn!(host = %host, "DNS lookup returned no addresses");
+                self.log_failure(message, None, "DNS lookup returned no addresses".into())
                     .await?;
+                breaker_guard.record_failure();
+                self.record_outcome("fatal", None, message, started_at);
                 return Ok(DispatchResult::FatalError);
             }
         };
 
+        // `breaker_guard` is intentionally not resolved on the `?` exits
+        // below (client build, payload serialization, webhook log writes):
+        // those are infra errors rather than a `DispatchResult`, and dropping the guard
+        // unresolved still records a failure for us if it held the probe.
         let client = Client::builder()
             .timeout(Duration::from_millis(message.timeout_ms))
             .resolve(host, target_addr)
@@ -139,23 +219,31 @@ impl WebhookDispatcher {
             }
         }
 
-        if let Some(secret) = hmac_secret {
-            if let Ok(sig) = header::HeaderValue::from_str(&sign_payload(&secret, &payload_json)) {
+        if !hmac_secrets.is_empty() {
+            let signatures = hmac_secrets
+                .iter()
+                .map(|secret| sign_payload(secret, &payload_json))
+                .collect::<Vec<_>>()
+                .join(",");
+            if let Ok(sig) = header::HeaderValue::from_str(&signatures) {
                 headers.insert("X-Firecrawl-Signature", sig);
             }
         }
 
         info!(url = %url, "Sending webhook");
 
-        match client
+        let send_result = client
             .post(url.as_str())
             .headers(headers)
             .body(payload_json)
             .send()
-            .await
-        {
+            .await;
+
+        let mut delivered_status_code: Option<i32> = None;
+        let result = match send_result {
             Ok(res) => {
                 let status = res.status();
+                delivered_status_code = Some(status.as_u16() as i32);
                 if status.is_success() {
                     info!(status = status.as_u16(), "Webhook delivered");
                     self.log_webhook(
@@ -165,7 +253,8 @@ impl WebhookDispatcher {
                         None,
                     )
                     .await?;
-                    Ok(DispatchResult::Success)
+                    breaker_guard.record_success();
+                    DispatchResult::Success
                 } else {
                     warn!(status = status.as_u16(), "Webhook server returned error");
                     self.log_webhook(
@@ -175,22 +264,145 @@ impl WebhookDispatcher {
                         Some(format!("HTTP Status {}", status)),
                     )
                     .await?;
+                    breaker_guard.record_failure();
 
                     // rate limits (429), timeouts (408), and server errors (5xx) are retryable
                     match status.as_u16() {
-                        429 | 408 | 500..=599 => Ok(DispatchResult::RetryableError),
-                        _ => Ok(DispatchResult::FatalError),
+                        429 | 408 | 500..=599 => DispatchResult::RetryableError {
+                            status_code: Some(status.as_u16() as i32),
+                            error: Some(format!("HTTP Status {}", status)),
+                        },
+                        _ => DispatchResult::FatalError,
                     }
                 }
             }
             Err(e) => {
                 let code = e.status().map(|s| s.as_u16() as i32);
+                delivered_status_code = code;
+                let error_message = format!("{:#}", e);
                 error!(error = ?e, "Webhook delivery failed");
-                self.log_webhook(message, false, code, Some(format!("{:#}", e)))
+                self.log_webhook(message, false, code, Some(error_message.clone()))
                     .await?;
-                Ok(DispatchResult::RetryableError)
+                breaker_guard.record_failure();
+                DispatchResult::RetryableError {
+                    status_code: code,
+                    error: Some(error_message),
+                }
             }
+        };
+
+        let outcome = match &result {
+            DispatchResult::Success => "success",
+            DispatchResult::FatalError => "fatal",
+            DispatchResult::RetryableError { .. } => "retryable",
+        };
+        self.record_outcome(outcome, delivered_status_code, message, started_at);
+
+        Ok(result)
+    }
+
+    /// Records one `dispatch()` outcome, including early-return paths that
+    /// never reach the HTTP call - a silent pre-send branch here would
+    /// undercount fatal/retryable deliveries.
+    fn record_outcome(
+        &self,
+        outcome: &str,
+        status_code: Option<i32>,
+        message: &WebhookQueueMessage,
+        started_at: Instant,
+    ) {
+        if let Some(metrics) = &self.metrics {
+            let latency_ms = started_at.elapsed().as_secs_f64() * 1000.0;
+            metrics.record_delivery(outcome, status_code, &message.event, latency_ms);
+        }
+    }
+
+    /// Persists an exhausted message to `webhook_dead_letters` so it isn't
+    /// lost permanently; see [`Self::replay_dead_letter`] to re-enqueue it.
+    pub async fn dead_letter(
+        &self,
+        message: &WebhookQueueMessage,
+        status_code: Option<i32>,
+        error: Option<String>,
+    ) -> Result<()> {
+        let row = WebhookDeadLetter {
+            team_id: &message.team_id,
+            job_id: &message.job_id,
+            payload: message,
+            status_code,
+            error,
+        };
+
+        let res = self
+            .postgrest
+            .from("webhook_dead_letters")
+            .insert(serde_json::to_string(&row)?)
+            .execute()
+            .await?;
+
+        if !res.status().is_success() {
+            let status_code = res.status().as_u16();
+            let body = res.text().await.unwrap_or_default();
+
+            error!(
+                status = status_code,
+                body = %body,
+                "Failed to persist dead letter"
+            );
+
+            // The caller only acks the original queue message once this
+            // returns `Ok`; swallowing the failure here would silently drop
+            // the message instead of dead-lettering it.
+            anyhow::bail!("Failed to persist dead letter: status {}", status_code);
         }
+
+        Ok(())
+    }
+
+    /// Re-enqueues a dead-lettered message by id directly onto `queue`'s
+    /// main queue (not the retry path), resetting its retry count so it
+    /// gets a fresh retry budget, then removes it from
+    /// `webhook_dead_letters`.
+    ///
+    /// The publish and the delete are not transactional: a crash between
+    /// the two leaves the message both re-enqueued and still present in
+    /// `webhook_dead_letters`, so a retried replay of the same id can
+    /// double-deliver it. Callers that need exactly-once replay should
+    /// de-dupe downstream.
+    pub async fn replay_dead_letter<Q: WebhookQueue>(&self, queue: &Q, id: &str) -> Result<()> {
+        let response = self
+            .postgrest
+            .from("webhook_dead_letters")
+            .select("payload")
+            .eq("id", id)
+            .single()
+            .execute()
+            .await
+            .context("Failed to fetch dead letter")?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Dead letter {} not found", id);
+        }
+
+        let row: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+        let mut message: WebhookQueueMessage = serde_json::from_value(
+            row.get("payload")
+                .cloned()
+                .context("Dead letter missing payload")?,
+        )?;
+        message.retry_count = 0;
+
+        let payload = serde_json::to_vec(&message)?;
+        queue.publish_main(&payload).await?;
+
+        self.postgrest
+            .from("webhook_dead_letters")
+            .eq("id", id)
+            .delete()
+            .execute()
+            .await?;
+
+        Ok(())
     }
 
     async fn log_failure(
@@ -241,3 +453,57 @@ impl WebhookDispatcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    fn row(not_before: Option<DateTime<Utc>>, not_after: Option<DateTime<Utc>>) -> HmacSecretRow {
+        HmacSecretRow {
+            secret: "s".into(),
+            not_before,
+            not_after,
+        }
+    }
+
+    #[test]
+    fn valid_with_no_bounds() {
+        assert!(row(None, None).is_valid_at(Utc::now()));
+    }
+
+    #[test]
+    fn not_yet_valid_before_not_before() {
+        let now = Utc::now();
+        let r = row(Some(now + ChronoDuration::seconds(60)), None);
+        assert!(!r.is_valid_at(now));
+    }
+
+    #[test]
+    fn valid_exactly_at_not_before() {
+        let now = Utc::now();
+        let r = row(Some(now), None);
+        assert!(r.is_valid_at(now));
+    }
+
+    #[test]
+    fn valid_just_before_not_after() {
+        let now = Utc::now();
+        let r = row(None, Some(now + ChronoDuration::seconds(1)));
+        assert!(r.is_valid_at(now));
+    }
+
+    #[test]
+    fn invalid_exactly_at_not_after() {
+        let now = Utc::now();
+        let r = row(None, Some(now));
+        assert!(!r.is_valid_at(now));
+    }
+
+    #[test]
+    fn invalid_after_not_after() {
+        let now = Utc::now();
+        let r = row(None, Some(now - ChronoDuration::seconds(1)));
+        assert!(!r.is_valid_at(now));
+    }
+}
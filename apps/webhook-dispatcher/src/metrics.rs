@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// OpenTelemetry instruments for webhook delivery: round-trip latency,
+/// success/fatal/retryable outcome counts labeled by status-code class and
+/// `event` type, and a gauge tracking how many deliveries are in flight.
+#[derive(Clone)]
+pub struct DispatchMetrics {
+    latency_ms: Histogram<f64>,
+    outcomes: Counter<u64>,
+    in_flight: Arc<AtomicI64>,
+}
+
+impl DispatchMetrics {
+    /// Initializes the OTLP metrics pipeline against `otel_endpoint` and
+    /// registers the instruments this module exposes.
+    pub fn init(otel_endpoint: &str) -> Result<Self> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(otel_endpoint);
+
+        let provider = opentelemetry_otlp::new_pipeline()
+            .metrics(opentelemetry_sdk::runtime::Tokio)
+            .with_exporter(exporter)
+            .build()
+            .context("Failed to build OTLP metrics pipeline")?;
+
+        global::set_meter_provider(provider);
+        let meter = global::meter("webhook-dispatcher");
+
+        let in_flight = Arc::new(AtomicI64::new(0));
+        let gauge_counter = in_flight.clone();
+        meter
+            .i64_observable_gauge("webhook_dispatcher.tasks_in_flight")
+            .with_description("Number of webhook deliveries currently being processed")
+            .with_callback(move |observer| observer.observe(gauge_counter.load(Ordering::Relaxed), &[]))
+            .init();
+
+        Ok(Self {
+            latency_ms: meter
+                .f64_histogram("webhook_dispatcher.delivery_latency_ms")
+                .with_description("Webhook round-trip latency")
+                .with_unit("ms")
+                .init(),
+            outcomes: meter
+                .u64_counter("webhook_dispatcher.delivery_outcomes")
+                .with_description("Webhook delivery outcomes by status class and event type")
+                .init(),
+            in_flight,
+        })
+    }
+
+    /// Records one completed delivery attempt.
+    pub fn record_delivery(&self, outcome: &str, status_code: Option<i32>, event: &str, latency_ms: f64) {
+        let labels = [
+            KeyValue::new("outcome", outcome.to_string()),
+            KeyValue::new("status_class", status_class(status_code)),
+            KeyValue::new("event", event.to_string()),
+        ];
+        self.latency_ms.record(latency_ms, &labels);
+        self.outcomes.add(1, &labels);
+    }
+
+    /// Sets the in-flight task gauge to `count`, e.g. `tasks.len()` from the
+    /// consumer's `JoinSet`.
+    pub fn set_in_flight(&self, count: i64) {
+        self.in_flight.store(count, Ordering::Relaxed);
+    }
+}
+
+fn status_class(status_code: Option<i32>) -> &'static str {
+    match status_code {
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        Some(_) => "other",
+        None => "none",
+    }
+}
@@ -0,0 +1,267 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_lite::StreamExt;
+use lapin::{
+    options::*,
+    types::{FieldTable, LongString},
+    BasicProperties, Channel, Connection, ConnectionProperties, Consumer,
+};
+use rand::Rng;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const QUEUE_NAME: &str = "webhooks";
+pub const RETRY_QUEUE_PREFIX: &str = "webhooks_retry_";
+
+/// Staged backoff delays (ms), one retry queue per level. An attempt whose
+/// `retry_count` exceeds the last level is held at the last level's delay.
+pub const RETRY_DELAYS_MS: &[u64] = &[5_000, 30_000, 120_000, 600_000, 1_800_000];
+
+/// Bounded jitter applied to a computed retry delay, as a fraction of it,
+/// to avoid a thundering herd of retries against a recovering endpoint.
+const RETRY_JITTER_FRACTION: f64 = 0.2;
+
+fn retry_queue_name(level: usize) -> String {
+    format!("{}{}", RETRY_QUEUE_PREFIX, level)
+}
+
+/// Maps a message's `retry_count` (the attempt about to be made, 1 for the
+/// first retry) to a backoff level, so the first organic retry lands on
+/// level 0 (5s) rather than level 1.
+fn retry_level(retry_count: u32) -> usize {
+    (retry_count.saturating_sub(1) as usize).min(RETRY_DELAYS_MS.len() - 1)
+}
+
+fn jittered_delay_ms(level: usize) -> u64 {
+    let base = RETRY_DELAYS_MS[level] as f64;
+    let jitter = base * RETRY_JITTER_FRACTION;
+    let offset = rand::thread_rng().gen_range(-jitter..=jitter);
+    (base + offset).max(0.0) as u64
+}
+
+/// A single message handed to the dispatcher by a `WebhookQueue`.
+pub trait QueueDelivery: Send + 'static {
+    /// The raw message body.
+    fn data(&self) -> &[u8];
+}
+
+/// Abstracts the broker a webhook job is pulled from, so the dispatch/retry
+/// core and `JoinSet` concurrency logic in `consumer` don't need to know
+/// whether they're talking to RabbitMQ, Redis, SQS, or an in-memory queue.
+#[async_trait]
+pub trait WebhookQueue: Clone + Send + Sync + 'static {
+    type Delivery: QueueDelivery;
+
+    /// Waits for the next delivery, or `None` once the queue is closed.
+    async fn consume(&self) -> Result<Option<Self::Delivery>>;
+
+    /// Acknowledges successful processing of `delivery`.
+    async fn ack(&self, delivery: Self::Delivery) -> Result<()>;
+
+    /// Negatively acknowledges `delivery`, requeueing it for another consumer.
+    async fn nack(&self, delivery: Self::Delivery) -> Result<()>;
+
+    /// Publishes `payload` onto the retry path, staged by `retry_count` so
+    /// later attempts wait longer than earlier ones.
+    async fn publish_retry(&self, payload: &[u8], retry_count: u32) -> Result<()>;
+
+    /// Publishes `payload` directly onto the main queue, bypassing the
+    /// retry delay path entirely. Used by dead-letter replay.
+    async fn publish_main(&self, payload: &[u8]) -> Result<()>;
+}
+
+pub struct RabbitMqDelivery(pub lapin::message::Delivery);
+
+impl QueueDelivery for RabbitMqDelivery {
+    fn data(&self) -> &[u8] {
+        &self.0.data
+    }
+}
+
+/// Default `WebhookQueue` implementor, backed by a RabbitMQ quorum queue
+/// with one dead-letter retry queue per backoff level, each message expiring
+/// after its own jittered delay.
+#[derive(Clone)]
+pub struct RabbitMqQueue {
+    channel: Channel,
+    consumer: Arc<Mutex<Consumer>>,
+}
+
+impl RabbitMqQueue {
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let conn = Connection::connect(&config.rabbitmq_url, ConnectionProperties::default())
+            .await
+            .context("RabbitMQ connect failed")?;
+        let channel = conn
+            .create_channel()
+            .await
+            .context("Channel create failed")?;
+
+        let mut args = FieldTable::default();
+        args.insert("x-queue-type".into(), LongString::from("quorum").into());
+
+        channel
+            .queue_declare(
+                QUEUE_NAME,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                args,
+            )
+            .await?;
+
+        for level in 0..RETRY_DELAYS_MS.len() {
+            // No `x-message-ttl` here: every publish to these queues goes
+            // through `publish_retry`, which always sets a per-message
+            // `expiration`. RabbitMQ honors the *lower* of the two, so a
+            // queue-level TTL here would clamp away the upward half of the
+            // jitter in `publish_retry`.
+            let mut retry_args = FieldTable::default();
+            retry_args.insert("x-dead-letter-exchange".into(), LongString::from("").into());
+            retry_args.insert(
+                "x-dead-letter-routing-key".into(),
+                LongString::from(QUEUE_NAME).into(),
+            );
+
+            channel
+                .queue_declare(
+                    &retry_queue_name(level),
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    retry_args,
+                )
+                .await?;
+        }
+
+        channel
+            .basic_qos(config.prefetch_count, BasicQosOptions::default())
+            .await?;
+
+        let consumer = channel
+            .basic_consume(
+                QUEUE_NAME,
+                "webhook-dispatcher",
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(Self {
+            channel,
+            consumer: Arc::new(Mutex::new(consumer)),
+        })
+    }
+}
+
+#[async_trait]
+impl WebhookQueue for RabbitMqQueue {
+    type Delivery = RabbitMqDelivery;
+
+    async fn consume(&self) -> Result<Option<Self::Delivery>> {
+        let mut consumer = self.consumer.lock().await;
+        match consumer.next().await {
+            Some(Ok(delivery)) => Ok(Some(RabbitMqDelivery(delivery))),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    async fn ack(&self, delivery: Self::Delivery) -> Result<()> {
+        delivery
+            .0
+            .ack(BasicAckOptions::default())
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn nack(&self, delivery: Self::Delivery) -> Result<()> {
+        delivery
+            .0
+            .nack(BasicNackOptions {
+                multiple: false,
+                requeue: true,
+            })
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn publish_retry(&self, payload: &[u8], retry_count: u32) -> Result<()> {
+        let level = retry_level(retry_count);
+        let delay_ms = jittered_delay_ms(level);
+        let properties = BasicProperties::default().with_expiration(delay_ms.to_string().into());
+
+        self.channel
+            .basic_publish(
+                "",
+                &retry_queue_name(level),
+                BasicPublishOptions::default(),
+                payload,
+                properties,
+            )
+            .await
+            .context("Failed to publish retry")?;
+        Ok(())
+    }
+
+    async fn publish_main(&self, payload: &[u8]) -> Result<()> {
+        self.channel
+            .basic_publish(
+                "",
+                QUEUE_NAME,
+                BasicPublishOptions::default(),
+                payload,
+                BasicProperties::default(),
+            )
+            .await
+            .context("Failed to publish to main queue")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_retry_maps_to_level_zero() {
+        // retry_count=1 is the first organic retry attempt; it must land on
+        // the shortest delay (level 0), not level 1.
+        assert_eq!(retry_level(1), 0);
+    }
+
+    #[test]
+    fn retry_level_tracks_retry_count_minus_one() {
+        assert_eq!(retry_level(2), 1);
+        assert_eq!(retry_level(3), 2);
+    }
+
+    #[test]
+    fn retry_level_clamps_at_the_last_delay() {
+        let last = RETRY_DELAYS_MS.len() as u32;
+        assert_eq!(retry_level(last), RETRY_DELAYS_MS.len() - 1);
+        assert_eq!(retry_level(last + 10), RETRY_DELAYS_MS.len() - 1);
+    }
+
+    #[test]
+    fn retry_queue_names_are_stable() {
+        assert_eq!(retry_queue_name(0), "webhooks_retry_0");
+        assert_eq!(retry_queue_name(4), "webhooks_retry_4");
+    }
+
+    #[test]
+    fn jittered_delay_stays_within_bounds() {
+        for level in 0..RETRY_DELAYS_MS.len() {
+            let base = RETRY_DELAYS_MS[level] as f64;
+            let bound = base * RETRY_JITTER_FRACTION;
+            for _ in 0..100 {
+                let delay = jittered_delay_ms(level) as f64;
+                assert!(delay >= (base - bound).max(0.0));
+                assert!(delay <= base + bound);
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Built-in deny ranges matching the private/loopback/link-local/
+/// documentation space firecrawl has always blocked by default, including
+/// the cloud metadata endpoint (169.254.169.254).
+fn default_deny_cidrs() -> Vec<IpNet> {
+    [
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "0.0.0.0/8",
+        "192.0.2.0/24",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "::1/128",
+        "::/128",
+        "fc00::/7",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|cidr| cidr.parse().expect("default SSRF deny CIDR is valid"))
+    .collect()
+}
+
+fn parse_cidrs(cidrs: &[String]) -> Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .with_context(|| format!("invalid SSRF CIDR: {}", cidr))
+        })
+        .collect()
+}
+
+/// Operator-configurable SSRF policy for resolving webhook destinations.
+/// `allow` takes precedence over `deny`, so a self-hoster can permit a
+/// specific internal target while keeping the built-in deny defaults for
+/// everything else.
+#[derive(Clone)]
+pub struct SsrfPolicy {
+    deny: Vec<IpNet>,
+    allow: Vec<IpNet>,
+}
+
+impl SsrfPolicy {
+    pub fn new(deny_cidrs: &[String], allow_cidrs: &[String]) -> Result<Self> {
+        let mut deny = default_deny_cidrs();
+        deny.extend(parse_cidrs(deny_cidrs)?);
+        let allow = parse_cidrs(allow_cidrs)?;
+        Ok(Self { deny, allow })
+    }
+
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.deny.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Returns `true` only if every address in `addrs` is safe to connect
+    /// to. Checking *all* resolved addresses, not just the one picked to
+    /// connect with, defends against multi-record DNS rebinding.
+    pub fn all_allowed(&self, addrs: &[IpAddr]) -> bool {
+        addrs.iter().all(|addr| !self.is_blocked(*addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(deny: &[&str], allow: &[&str]) -> SsrfPolicy {
+        let deny: Vec<String> = deny.iter().map(|s| s.to_string()).collect();
+        let allow: Vec<String> = allow.iter().map(|s| s.to_string()).collect();
+        SsrfPolicy::new(&deny, &allow).unwrap()
+    }
+
+    #[test]
+    fn blocks_default_private_ranges() {
+        let p = policy(&[], &[]);
+        assert!(!p.all_allowed(&["10.0.0.5".parse().unwrap()]));
+        assert!(!p.all_allowed(&["192.168.1.1".parse().unwrap()]));
+        assert!(!p.all_allowed(&["127.0.0.1".parse().unwrap()]));
+        assert!(!p.all_allowed(&["::1".parse().unwrap()]));
+    }
+
+    #[test]
+    fn blocks_cloud_metadata_endpoint() {
+        let p = policy(&[], &[]);
+        assert!(!p.all_allowed(&["169.254.169.254".parse().unwrap()]));
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        let p = policy(&[], &[]);
+        assert!(p.all_allowed(&["93.184.216.34".parse().unwrap()]));
+    }
+
+    #[test]
+    fn operator_deny_cidr_blocks_in_addition_to_defaults() {
+        let p = policy(&["93.184.216.34/32"], &[]);
+        assert!(!p.all_allowed(&["93.184.216.34".parse().unwrap()]));
+    }
+
+    #[test]
+    fn allow_takes_precedence_over_deny() {
+        let p = policy(&[], &["10.0.0.0/24"]);
+        assert!(p.all_allowed(&["10.0.0.5".parse().unwrap()]));
+        // Addresses outside the allow range are still denied by defaults.
+        assert!(!p.all_allowed(&["10.0.1.5".parse().unwrap()]));
+    }
+
+    #[test]
+    fn rejects_if_any_resolved_address_is_blocked() {
+        let p = policy(&[], &[]);
+        let addrs = ["93.184.216.34".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        assert!(!p.all_allowed(&addrs));
+    }
+}